@@ -1,7 +1,9 @@
 use anyhow::{format_err, Context};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use image::ImageOutputFormat;
 
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 mod logging;
 
@@ -41,30 +43,35 @@ enum OutputFormat {
     Gif,
     Png,
     Svg,
+    Mp4,
+    Webm,
+    Webp,
 }
 
-fn execute_cli() -> anyhow::Result<()> {
+fn build_app() -> clap::App<'static, 'static> {
     use clap::{crate_authors, crate_version, App, AppSettings, Arg};
     #[rustfmt::skip]
-    let args = App::new("cast2gif")
+    let app = App::new("cast2gif")
         .version(crate_version!())
         .author(crate_authors!())
-        .about("Renders Asciinema .cast files as gif, svg, or animated png.")
+        .about("Renders Asciinema .cast files as gif, svg, animated png, mp4, webm, or webp, or \
+                extracts a poster frame / BlurHash placeholder.")
         .setting(AppSettings::ColoredHelp)
         .setting(AppSettings::ArgRequiredElseHelp)
         .arg(Arg::with_name("cast_file")
-            .help("The asciinema .cast file to render")
+            .help("The asciinema .cast file to render. Pass `-` to read from stdin.")
             .required(true))
         .arg(Arg::with_name("out_file")
-            .help("The file to render to")
-            .required(true))
+            .help("The file to render to. Pass `-` to write to stdout. Not needed with \
+                   --blurhash, which only prints to stdout.")
+            .required_unless("blurhash"))
         .arg(Arg::with_name("format")
             .long("format")
             .short("F")
             .help("The file format to render to. This will be automatically determined from the \
                    file extension if not specified.")
             .takes_value(true)
-            .possible_values(&["gif", "svg", "png"]))
+            .possible_values(&["gif", "svg", "png", "mp4", "webm", "webp"]))
         .arg(Arg::with_name("force")
             .long("force")
             .short("f")
@@ -74,7 +81,35 @@ fn execute_cli() -> anyhow::Result<()> {
             .short("i")
             .help("The interval at which frames from the recording are rendered")
             .default_value("0.1"))
-        .get_matches();
+        .arg(Arg::with_name("poster")
+            .long("poster")
+            .help("Render a single still frame as a PNG or JPEG instead of an animation, at the \
+                   given playback time in seconds, e.g. --poster=5. Bare --poster (no =SECONDS) \
+                   defaults to the last frame.")
+            .takes_value(true)
+            .require_equals(true)
+            .min_values(0)
+            .max_values(1))
+        .arg(Arg::with_name("blurhash")
+            .long("blurhash")
+            .help("Print a BlurHash placeholder string for a representative frame to stdout \
+                   instead of rendering an output file. Honors --poster's timestamp, if given."))
+        .arg(Arg::with_name("optimize")
+            .long("optimize")
+            .help("Re-optimize GIF output with gifsicle. LEVEL is gifsicle's -O level (1-3, \
+                   default 3) and may be followed by a comma and a lossy quality (e.g. \
+                   `--optimize=3,80`). Bare --optimize (no =LEVEL) uses level 3. Falls back to \
+                   the unoptimized GIF with a warning if gifsicle isn't installed.")
+            .takes_value(true)
+            .require_equals(true)
+            .min_values(0)
+            .max_values(1));
+
+    app
+}
+
+fn execute_cli() -> anyhow::Result<()> {
+    let args = build_app().get_matches();
 
     let interval: f32 = args
         .value_of("frame_interval")
@@ -82,70 +117,125 @@ fn execute_cli() -> anyhow::Result<()> {
         .parse()
         .context("Could not parse frame interval")?;
 
+    let optimize_level: Option<OptimizeLevel> = if args.is_present("optimize") {
+        Some(match args.value_of("optimize") {
+            Some(spec) => spec.parse().context("Could not parse --optimize value")?,
+            None => OptimizeLevel::default(),
+        })
+    } else {
+        None
+    };
+
     // Load cast file
     let cast_file_path = args
         .value_of("cast_file")
         .expect("Missing required argument: cast_file");
-    let cast_file = std::fs::OpenOptions::new()
-        .read(true)
-        .open(cast_file_path)
+    let cast_file = open_cast_reader(cast_file_path)
         .context(format!("Could not open cast file: {}", cast_file_path))?;
 
-    // Get output path
-    let out_file_path = Path::new(
-        args.value_of("out_file")
-            .expect("Missing required argument: out_file"),
-    );
+    // A blurhash request only needs a single rasterized frame, so it short-circuits before we
+    // ever touch the output path.
+    if args.is_present("blurhash") {
+        let timestamp: Option<f32> = args
+            .value_of("poster")
+            .map(|s| s.parse().context("Could not parse --poster timestamp"))
+            .transpose()?;
 
-    // Make sure out path doesn't exist
-    if out_file_path.exists() && !args.is_present("force") {
-        return Err(format_err!(
-            "Output file already exists: {}",
-            out_file_path.to_string_lossy()
-        ));
+        let frame = crate::render_poster_frame(cast_file, timestamp)
+            .context("Could not rasterize frame for blurhash")?;
+        let rgba = frame.to_rgba8();
+        let hash = blurhash::encode(4, 3, rgba.width(), rgba.height(), &rgba.into_raw())
+            .context("Could not compute blurhash")?;
+        println!("{}", hash);
+
+        return Ok(());
     }
 
-    // Open out file
-    let out_file = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(out_file_path)
-        .context(format!(
-            "Could not open output file: {}",
-            out_file_path.to_string_lossy()
-        ))?;
+    // Get output path. `out_path` is `None` when writing to stdout, since there's no real file
+    // on disk to check for existence, guess a format from, or hand to gifsicle.
+    let out_file_arg = args
+        .value_of("out_file")
+        .expect("Missing required argument: out_file");
+    let (mut out_file, out_path) = open_out_writer(out_file_arg, args.is_present("force"))
+        .context(format!("Could not open output file: {}", out_file_arg))?;
+
+    // A poster request short-circuits the whole animation pipeline: rasterize a single frame
+    // and write it out as a still image instead of sequencing a GIF/video/etc.
+    if args.is_present("poster") {
+        let timestamp: Option<f32> = args
+            .value_of("poster")
+            .map(|s| s.parse().context("Could not parse --poster timestamp"))
+            .transpose()?;
+
+        let frame = crate::render_poster_frame(cast_file, timestamp)
+            .context("Could not rasterize poster frame")?;
+
+        if let Some(fmt) = args.value_of("format") {
+            if fmt != "png" {
+                log::warn!(
+                    "--poster always writes a PNG/JPEG still image; ignoring --format {}",
+                    fmt
+                );
+            }
+        }
+
+        let poster_ext = out_path
+            .as_ref()
+            .and_then(|p| p.extension())
+            .map(|e| e.to_string_lossy().to_lowercase());
+        let poster_format = match poster_ext.as_deref() {
+            Some("jpg") | Some("jpeg") => ImageOutputFormat::Jpeg(90),
+            Some("png") => ImageOutputFormat::Png,
+            Some(other) => {
+                log::warn!(
+                    "--poster writes a PNG/JPEG still, but the output extension `.{}` doesn't \
+                     match; writing PNG bytes anyway",
+                    other
+                );
+                ImageOutputFormat::Png
+            }
+            None => ImageOutputFormat::Png,
+        };
+
+        frame
+            .write_to(&mut out_file, poster_format)
+            .context("Could not write poster frame")?;
+
+        return Ok(());
+    }
 
     let format = match args.value_of("format") {
         // Guess format from file extension
         None => {
-            let warn_message = "Could not detect output format from file extension, assuming gif \
-                                format. Use --format to specify otherwise.";
-            if let Some(ext) = out_file_path.extension() {
-                let ext = ext.to_string_lossy().to_lowercase();
-                match ext.as_str() {
-                    "gif" => OutputFormat::Gif,
-                    "svg" => OutputFormat::Svg,
-                    "png" => OutputFormat::Png,
-                    _ => {
-                        log::warn!("{}", warn_message);
-                        OutputFormat::Gif
-                    }
-                }
-            } else {
-                log::warn!("{}", warn_message);
-                OutputFormat::Gif
-            }
+            let out_path = out_path.as_deref().ok_or_else(|| {
+                format_err!(
+                    "Could not detect an output format when writing to stdout; pass one of: \
+                     --format gif, --format svg, --format png, --format mp4, --format webm, \
+                     --format webp."
+                )
+            })?;
+            detect_format(out_path)?
         }
         // Use seleted output format
         Some("gif") => OutputFormat::Gif,
         Some("svg") => OutputFormat::Svg,
         Some("png") => OutputFormat::Png,
+        Some("mp4") => OutputFormat::Mp4,
+        Some("webm") => OutputFormat::Webm,
+        Some("webp") => OutputFormat::Webp,
         Some(other) => panic!("Invalid option to --format: {}", other),
     };
 
-    // Create the progress bars
+    if optimize_level.is_some() && !matches!(format, OutputFormat::Gif) {
+        log::warn!("--optimize only applies to GIF output; ignoring it for this format");
+    }
+
+    // Create the progress bars. When the rendered output itself is going to stdout, the bars
+    // are drawn to stderr instead so they don't corrupt the binary stream.
     let multi = MultiProgress::new();
+    if out_path.is_none() {
+        multi.set_draw_target(ProgressDrawTarget::stderr());
+    }
     let template =
         "{prefix:12} [{elapsed_precise:.dim}]: {wide_bar:.green/white} {pos:>7}/{len:7} ( {eta_precise:.dim} )";
     let raster_progress =
@@ -162,7 +252,67 @@ fn execute_cli() -> anyhow::Result<()> {
             std::thread::spawn(move || {
                 crate::convert_to_gif_with_progress(
                     cast_file,
-                    &out_file,
+                    out_file,
+                    interval,
+                    progress_handler,
+                )
+                .expect("TODO");
+            });
+            multi.join_and_clear().expect("TODO");
+
+            if let Some(level) = optimize_level {
+                match &out_path {
+                    Some(path) => {
+                        let optimizer = GifsicleOptimizer;
+                        if optimizer.is_available() {
+                            if let Err(e) = optimizer.optimize(path, level) {
+                                log::warn!(
+                                    "gifsicle optimization failed, keeping unoptimized output: {:?}",
+                                    e
+                                );
+                            }
+                        } else {
+                            log::warn!(
+                                "--optimize was requested but `gifsicle` was not found on PATH; \
+                                 keeping unoptimized output"
+                            );
+                        }
+                    }
+                    None => log::warn!(
+                        "--optimize has no effect when writing GIF output to stdout; keeping \
+                         unoptimized output"
+                    ),
+                }
+            }
+        }
+        OutputFormat::Mp4 | OutputFormat::Webm => {
+            let codec = match format {
+                OutputFormat::Mp4 => VideoCodec::H264,
+                OutputFormat::Webm => VideoCodec::Vp9,
+                _ => unreachable!(),
+            };
+            let ffmpeg = find_ffmpeg().context(
+                "ffmpeg is required to encode mp4/webm output but was not found on your PATH. \
+                 Install ffmpeg and make sure it's available as `ffmpeg`.",
+            )?;
+            std::thread::spawn(move || {
+                crate::convert_to_video_with_progress(
+                    cast_file,
+                    out_file,
+                    interval,
+                    progress_handler,
+                    ffmpeg,
+                    codec,
+                )
+                .expect("TODO");
+            });
+            multi.join_and_clear().expect("TODO");
+        }
+        OutputFormat::Webp => {
+            std::thread::spawn(move || {
+                crate::convert_to_webp_with_progress(
+                    cast_file,
+                    out_file,
                     interval,
                     progress_handler,
                 )
@@ -179,6 +329,158 @@ fn execute_cli() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Codec to ask the external `ffmpeg` binary to encode frames with.
+enum VideoCodec {
+    H264,
+    Vp9,
+}
+
+// Find ffmpeg on PATH, or error with a clear explanation if it isn't installed.
+fn find_ffmpeg() -> anyhow::Result<std::path::PathBuf> {
+    which::which("ffmpeg").map_err(|_| format_err!("Could not find `ffmpeg` binary"))
+}
+
+// Treat `-` as stdin.
+fn open_cast_reader(path: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+    if path == "-" {
+        Ok(Box::new(std::io::stdin()))
+    } else {
+        let file = std::fs::OpenOptions::new().read(true).open(path)?;
+        Ok(Box::new(file))
+    }
+}
+
+// Treat `-` as stdout; the returned path is None in that case, since format auto-detection and
+// gifsicle optimization both need a real path on disk.
+fn open_out_writer(
+    path: &str,
+    force: bool,
+) -> anyhow::Result<(Box<dyn Write + Send>, Option<PathBuf>)> {
+    if path == "-" {
+        // `Stdout` is internally line-buffered, which would force a flush on every `0x0A` byte
+        // in the binary output; wrap it so encoders get a real block buffer instead.
+        return Ok((Box::new(std::io::BufWriter::new(std::io::stdout())), None));
+    }
+
+    let path = Path::new(path);
+
+    // Make sure out path doesn't exist
+    if path.exists() && !force {
+        return Err(format_err!(
+            "Output file already exists: {}",
+            path.to_string_lossy()
+        ));
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+
+    Ok((Box::new(file), Some(path.to_path_buf())))
+}
+
+// Guess the output format from the extension, recognizing aliases like `.apng`/`.m4v`.
+fn detect_format(out_file_path: &Path) -> anyhow::Result<OutputFormat> {
+    let ext = out_file_path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+
+    match ext.as_deref() {
+        Some("gif") => Ok(OutputFormat::Gif),
+        Some("svg") => Ok(OutputFormat::Svg),
+        Some("png") | Some("apng") => Ok(OutputFormat::Png),
+        Some("mp4") | Some("m4v") => Ok(OutputFormat::Mp4),
+        Some("webm") => Ok(OutputFormat::Webm),
+        Some("webp") => Ok(OutputFormat::Webp),
+        _ => {
+            let described = match ext {
+                Some(ext) => format!("the extension `.{}`", ext),
+                None => "a file with no extension".to_string(),
+            };
+            Err(format_err!(
+                "Could not detect an output format from {} on \"{}\". Supported extensions \
+                 (and aliases) are: .gif, .svg, .png (.apng), .mp4 (.m4v), .webm, .webp. Since \
+                 there's exactly one output file, pass the matching flag directly, e.g. one of: \
+                 --format gif, --format svg, --format png, --format mp4, --format webm, \
+                 --format webp.",
+                described,
+                out_file_path.to_string_lossy(),
+            ))
+        }
+    }
+}
+
+// Parsed --optimize value.
+#[derive(Clone, Copy)]
+struct OptimizeLevel {
+    level: u8,
+    lossy: Option<u8>,
+}
+
+impl Default for OptimizeLevel {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            lossy: None,
+        }
+    }
+}
+
+impl std::str::FromStr for OptimizeLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ',');
+        let level = parts
+            .next()
+            .unwrap()
+            .parse()
+            .context("Invalid gifsicle -O level")?;
+        let lossy = parts
+            .next()
+            .map(|l| l.parse().context("Invalid gifsicle --lossy value"))
+            .transpose()?;
+        Ok(Self { level, lossy })
+    }
+}
+
+// Pluggable backend for post-processing an encoded GIF (e.g. a future pngquant backend for APNG).
+trait GifOptimizer {
+    fn is_available(&self) -> bool;
+    fn optimize(&self, path: &Path, level: OptimizeLevel) -> anyhow::Result<()>;
+}
+
+struct GifsicleOptimizer;
+
+impl GifOptimizer for GifsicleOptimizer {
+    fn is_available(&self) -> bool {
+        which::which("gifsicle").is_ok()
+    }
+
+    fn optimize(&self, path: &Path, level: OptimizeLevel) -> anyhow::Result<()> {
+        let optimized_path = path.with_extension("gifsicle-tmp.gif");
+
+        let mut command = std::process::Command::new("gifsicle");
+        command.arg(format!("-O{}", level.level));
+        if let Some(lossy) = level.lossy {
+            command.arg(format!("--lossy={}", lossy));
+        }
+        command.arg(path).arg("-o").arg(&optimized_path);
+
+        let status = command.status().context("Could not run gifsicle")?;
+        if !status.success() {
+            return Err(format_err!("gifsicle exited with status: {}", status));
+        }
+
+        std::fs::rename(&optimized_path, path)
+            .context("Could not replace output with gifsicle-optimized version")?;
+
+        Ok(())
+    }
+}
+
 struct ProgressHandler {
     raster_progress: ProgressBar,
     sequence_progress: ProgressBar,
@@ -225,3 +527,55 @@ impl crate::types::CastProgressHandler for ProgressHandler {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Regression test: --poster takes an optional value, and without `require_equals` clap
+    // greedily grabs the next positional as that value, breaking `in.cast --poster out.png`.
+    #[test]
+    fn poster_flag_does_not_swallow_out_file() {
+        let matches = build_app()
+            .get_matches_from_safe(vec!["cast2gif", "in.cast", "--poster", "out.png"])
+            .expect("should parse");
+        assert_eq!(matches.value_of("out_file"), Some("out.png"));
+        assert!(matches.is_present("poster"));
+        assert_eq!(matches.value_of("poster"), None);
+    }
+
+    #[test]
+    fn poster_flag_with_equals_binds_a_value() {
+        let matches = build_app()
+            .get_matches_from_safe(vec!["cast2gif", "in.cast", "out.png", "--poster=5"])
+            .expect("should parse");
+        assert_eq!(matches.value_of("poster"), Some("5"));
+    }
+
+    // Same bug as --poster: a bare --optimize must not swallow out_file as its LEVEL value.
+    #[test]
+    fn optimize_flag_does_not_swallow_out_file() {
+        let matches = build_app()
+            .get_matches_from_safe(vec!["cast2gif", "in.cast", "--optimize", "out.gif"])
+            .expect("should parse");
+        assert_eq!(matches.value_of("out_file"), Some("out.gif"));
+        assert!(matches.is_present("optimize"));
+        assert_eq!(matches.value_of("optimize"), None);
+    }
+
+    #[test]
+    fn optimize_flag_with_equals_binds_a_value() {
+        let matches = build_app()
+            .get_matches_from_safe(vec!["cast2gif", "in.cast", "out.gif", "--optimize=2,80"])
+            .expect("should parse");
+        assert_eq!(matches.value_of("optimize"), Some("2,80"));
+    }
+
+    // --blurhash only prints to stdout, so it shouldn't force a throwaway out_file argument.
+    #[test]
+    fn blurhash_flag_works_without_out_file() {
+        build_app()
+            .get_matches_from_safe(vec!["cast2gif", "in.cast", "--blurhash"])
+            .expect("should parse");
+    }
+}